@@ -1,8 +1,13 @@
 use std::{collections::HashMap, env, io::IsTerminal, os::fd::{AsFd, AsRawFd}};
 
 use argh::{from_env, FromArgs};
-use log::debug;
-use nix::{errno::Errno, sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios}, unistd::isatty};
+use futures_util::StreamExt;
+use log::{debug, error};
+use nix::{
+    errno::Errno, pty::Winsize,
+    sys::{signal::{raise, Signal}, termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios}},
+    unistd::isatty,
+};
 use pty_process::Pty;
 use tokio::{
     io::{copy, copy_bidirectional, join, stderr, stdin, stdout, Join, Stdin, Stdout},
@@ -11,6 +16,8 @@ use tokio::{
 };
 use zbus::{proxy, zvariant::OwnedFd, Connection, Result};
 
+nix::ioctl_read_bad!(tiocgwinsz, nix::libc::TIOCGWINSZ, Winsize);
+
 #[derive(Debug, FromArgs)]
 /// Top-level command.
 struct Cli {
@@ -22,6 +29,10 @@ struct Cli {
     #[argh(switch, short = 'i')]
     interactive: bool,
 
+    /// keep our own HOME, USER and SHELL instead of the target user's
+    #[argh(switch)]
+    preserve_env: bool,
+
     /// the appliation to run
     #[argh(positional)]
     program: String,
@@ -48,7 +59,10 @@ trait EleProcess {
     async fn environment(&self, environ: HashMap<String, String>) -> Result<()>;
     async fn directory(&self, path: &str) -> Result<()>;
     async fn signal(&self, signal: i32) -> Result<()>;
+    async fn resize(&self, rows: u16, cols: u16) -> Result<()>;
     async fn spawn(&self) -> Result<Vec<OwnedFd>>;
+    #[zbus(signal)]
+    fn exited(&self, code: i32, signal: i32) -> Result<()>;
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -74,10 +88,18 @@ async fn main() -> Result<()> {
             .expect("failed to get current directory")
     ).await?;
     debug!("passing environment...");
-    process.environment(
-        HashMap::from_iter(env::vars())
-    ).await?;
-    // TODO: environment and resize
+    let mut environ = HashMap::from_iter(env::vars());
+    if !cli.preserve_env {
+        // let the daemon fill these in with the target user's own values;
+        // otherwise every caller's shell would override them, since almost
+        // every shell sets them
+        for key in ["HOME", "USER", "SHELL"] {
+            environ.remove(key);
+        }
+    }
+    process.environment(environ).await?;
+    // subscribe before spawning so we can't miss the signal
+    let mut exited_stream = process.receive_exited().await?;
     debug!("Spawning process...");
     let attached_to = process.spawn().await?;
     let mut stdin = stdin();
@@ -89,6 +111,21 @@ async fn main() -> Result<()> {
         let mut terminal = join(&mut stdin, &mut stdout);
         // set the tty as raw
         let old_attrs = set_raw(&mut terminal)?;
+        // tell the daemon about our current size and keep it updated
+        // whenever our terminal is resized
+        send_winsize(&process).await?;
+        tokio::spawn({
+            let process = process.clone();
+            async move {
+                let mut stream = signal(SignalKind::window_change()).unwrap();
+                loop {
+                    stream.recv().await;
+                    if let Err(e) = send_winsize(&process).await {
+                        error!("failed to forward new terminal size: {e}");
+                    }
+                }
+            }
+        });
         // in a raw tty, the shell on the other side will handle ^c and ^z,
         // so we don't have to
         copy_bidirectional(&mut pty, &mut terminal).await?;
@@ -108,24 +145,74 @@ async fn main() -> Result<()> {
             std::os::fd::OwnedFd::from(fd_iter.next().unwrap())
         ))?;
         let mut stderr = stderr();
-        // we have to pass signals over
-        tokio::spawn(async move {
-            let kind = SignalKind::interrupt();
-            let mut stream = signal(kind).unwrap();
-            loop {
-                stream.recv().await;
-                process.signal(kind.as_raw_value() as i32).await.unwrap();
-            }
-        });
+        // we have to pass signals over, so the root process can be
+        // backgrounded, suspended and terminated just like a local child
+        for kind in [
+            SignalKind::interrupt(),
+            SignalKind::terminate(),
+            SignalKind::hangup(),
+            SignalKind::quit(),
+            SignalKind::user_defined1(),
+            SignalKind::user_defined2(),
+            SignalKind::from_raw(nix::libc::SIGCONT),
+        ] {
+            let process = process.clone();
+            tokio::spawn(async move {
+                let mut stream = signal(kind).unwrap();
+                loop {
+                    stream.recv().await;
+                    if let Err(e) = process.signal(kind.as_raw_value() as i32).await {
+                        error!("failed to forward signal {kind:?}: {e}");
+                    }
+                }
+            });
+        }
+        // SIGTSTP needs special handling: forwarding it to the remote child
+        // isn't enough, `ele` itself also has to stop, or the invoking
+        // shell's job control (`^Z`/`bg`/`fg`) won't see it as stopped
+        {
+            let process = process.clone();
+            tokio::spawn(async move {
+                let mut stream = signal(SignalKind::from_raw(nix::libc::SIGTSTP)).unwrap();
+                loop {
+                    stream.recv().await;
+                    if let Err(e) = process.signal(nix::libc::SIGTSTP).await {
+                        error!("failed to forward signal SIGTSTP: {e}");
+                    }
+                    // actually stop ourselves, the way a process is supposed
+                    // to implement a custom SIGTSTP handler: SIGSTOP can't
+                    // be caught, so this reliably suspends us until SIGCONT
+                    if let Err(e) = raise(Signal::SIGSTOP) {
+                        error!("failed to stop ourselves: {e}");
+                    }
+                }
+            });
+        }
         tokio::spawn(async move { copy(&mut stdin, &mut child_stdin).await });
         tokio::spawn(async move { copy(&mut child_stdout, &mut stdout).await });
         copy(&mut child_stderr, &mut stderr).await?;
     }
-
-    Ok(())
+    debug!("process finished, waiting for the exit status...");
+    let message = exited_stream.next().await
+        .expect("daemon didn't report an exit status");
+    let args = message.args()?;
+    let (code, signal) = (*args.code(), *args.signal());
+    std::process::exit(if signal != 0 { 128 + signal } else { code });
 }
 
 
+/// Reads the current size of our controlling terminal and passes it on
+/// to the remote process.
+async fn send_winsize(process: &EleProcessProxy<'_>) -> Result<()> {
+    if !isatty(nix::libc::STDOUT_FILENO)? {
+        debug!("stdout is not connected to a tty, not sending its size");
+        return Ok(());
+    }
+    let mut winsize = Winsize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+    unsafe { tiocgwinsz(nix::libc::STDOUT_FILENO, &mut winsize)? };
+    process.resize(winsize.ws_row, winsize.ws_col).await
+}
+
 /// Sets the tty to raw mode (if it is a tty).
 /// 
 /// Returns the original mode.
@@ -1,11 +1,25 @@
-use std::{collections::HashMap, env, error, ops::Deref, os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd}, time::Duration};
-use log::{debug, error, trace};
-use pty_process::{Command, Pty};
-use tokio::{process::Child, sync::RwLock, time::sleep};
-use zbus::{connection, fdo::Error, interface, message::Header, object_server::InterfaceRef, zvariant::Fd, Connection, ObjectServer};
+use std::{
+    collections::HashMap, env, error, ffi::CString,
+    os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd}, os::unix::process::ExitStatusExt,
+};
+use log::{debug, error};
+use nix::{
+    libc::c_int,
+    sys::signal::{kill, Signal},
+    unistd::{setgid, setgroups, setuid, Gid, Pid, User as NixUser},
+};
+use pty_process::{Command, Pty, Size};
+use tokio::process::Child;
+use zbus::{
+    connection, fdo::Error, interface, message::Header,
+    object_server::{InterfaceRef, SignalEmitter}, zvariant::Fd, Connection, ObjectServer,
+};
 use zbus_polkit::policykit1::{AuthorityProxy, CheckAuthorizationFlags, Subject};
 
-static PROCESS_IDS: RwLock<Vec<usize>> = RwLock::const_new(Vec::new());
+/// The polkit action the caller has to be authorized for.
+///
+/// See `data/de.ytvwld.Ele.policy` for its description and message.
+const ACTION_ID: &str = "de.ytvwld.Ele.run";
 
 struct EleD {
     /// The ID to give to the next spawned process.
@@ -20,7 +34,10 @@ impl EleD {
         Self { next_id: 1 }
     }
 
-    async fn check_authorization(connection: &Connection, header: &Header<'_>) -> Result<(), Error> {
+    async fn check_authorization(
+        connection: &Connection, header: &Header<'_>,
+        program: &str, argv: &[&str], user: &str,
+    ) -> Result<(), Error> {
         debug!("checking authorization...");
         let polkit = AuthorityProxy::new(&connection).await?;
         let subject = Subject::new_for_message_header(header)
@@ -31,10 +48,16 @@ impl EleD {
                 zbus_polkit::Error::MissingSender => Error::InconsistentMessage("missing sender".to_string()),
                 i => Error::AuthFailed(i.to_string()),
             })?;
+        let argv = argv.join(" ");
+        let details = HashMap::from([
+            ("program", program),
+            ("argv", argv.as_str()),
+            ("user", user),
+        ]);
         let result = polkit.check_authorization(
             &subject,
-            "org.freedesktop.policykit.exec", // TODO: use a custom one
-            &HashMap::new(),
+            ACTION_ID,
+            &details,
             CheckAuthorizationFlags::AllowUserInteraction.into(),
             "",
         ).await?;
@@ -61,46 +84,103 @@ impl EleD {
             .as_str()
             .to_string();
         debug!("Client {} has asked us to execute {:?} as {}.", sender, argv, user);
-        assert_eq!(user, "root"); // TODO
-        Self::check_authorization(connection, &header).await?;
-        let process = EleProcess::new(sender, argv)?;
+        let program = *argv.first().ok_or(
+            Error::InvalidArgs("command is missing".to_string())
+        )?;
+        Self::check_authorization(connection, &header, program, &argv, user).await?;
         let id = self.next_id;
-        PROCESS_IDS.write().await.push(id);
         self.next_id += 1;
         let path = format!("/de/ytvwld/Ele/{id}");
+        let process = EleProcess::new(sender, argv, path.clone(), user)?;
         debug!("Registering object at {path}...");
         object_server.at(path.clone(), process).await?;
         Ok(path)
     }
 }
 
+/// Looks up the supplementary groups `user` belongs to; `gid` (the user's
+/// primary group) is always included in the result.
+fn supplementary_groups(user: &str, gid: Gid) -> Result<Vec<Gid>, Error> {
+    let user = CString::new(user).map_err(|e| Error::InvalidArgs(e.to_string()))?;
+    let mut ngroups: c_int = 32;
+    loop {
+        let mut groups = vec![0; ngroups as usize];
+        let mut found = ngroups;
+        // SAFETY: `groups` has room for `found` entries, as required
+        let ret = unsafe {
+            nix::libc::getgrouplist(user.as_ptr(), gid.as_raw(), groups.as_mut_ptr(), &mut found)
+        };
+        if ret >= 0 {
+            groups.truncate(found as usize);
+            return Ok(groups.into_iter().map(Gid::from_raw).collect());
+        } else if found > ngroups {
+            ngroups = found;
+        } else {
+            ngroups *= 2;
+        }
+    }
+}
+
 /// A process that might be running.
-/// 
+///
 /// All that we know is that the caller has been successfully authenticated
 /// to run this process.
 struct EleProcess {
     /// the unique name of the client that created this process
     sender: String,
+    /// the dbus object path this process is registered at
+    path: String,
     pty: Option<Pty>,
     command: Command,
-    child: Option<Child>,
+    /// `HOME`/`USER`/`SHELL` for the target user, applied in `spawn()` for
+    /// any of these keys the caller hasn't set via `environment()`
+    user_env_defaults: HashMap<&'static str, String>,
+    /// the keys the caller has explicitly set via `environment()`
+    env_overridden: std::collections::HashSet<String>,
+    /// the pid of the running child, once it has been spawned
+    pid: Option<u32>,
 }
 
 impl EleProcess {
     /// Create a new process.
-    /// 
+    ///
     /// We *need* to make sure that the caller is authenticated to perform this
     /// action *beforehand*.
-    fn new(sender: String, argv: Vec<&str>) -> Result<Self, Error> {
+    fn new(sender: String, argv: Vec<&str>, path: String, user: &str) -> Result<Self, Error> {
         debug!("Creating pty...");
         let pty = Pty::new()
             .map_err(|e| Error::SpawnFailed(e.to_string()))?;
+        let passwd = NixUser::from_name(user).map_err(
+            |e| Error::Failed(e.to_string())
+        )?.ok_or(Error::InvalidArgs(format!("user {user} does not exist")))?;
+        let groups = supplementary_groups(&passwd.name, passwd.gid)?;
         let mut argv_iter = argv.iter();
         let mut command = Command::new(argv_iter.next().ok_or(
             Error::InvalidArgs("command is missing".to_string())
         )?);
         command.args(argv_iter);
-        Ok(Self { sender, pty: Some(pty), command, child: None })
+        // these are only defaults: applied in `spawn()`, after `environment()`
+        // has had a chance to run, and only for keys the caller didn't set
+        let user_env_defaults = HashMap::from([
+            ("HOME", passwd.dir.clone()),
+            ("USER", passwd.name.clone()),
+            ("SHELL", passwd.shell.clone()),
+        ]);
+        let (uid, gid) = (passwd.uid, passwd.gid);
+        // SAFETY: setgroups/setgid/setuid are async-signal-safe
+        unsafe {
+            command.pre_exec(move || {
+                setgroups(&groups)?;
+                setgid(gid)?;
+                setuid(uid)?;
+                Ok(())
+            });
+        }
+        Ok(Self {
+            sender, path, pty: Some(pty), command,
+            user_env_defaults, env_overridden: std::collections::HashSet::new(),
+            pid: None,
+        })
     }
 
     fn check_caller(&self, header: Header<'_>) -> Result<(), Error> {
@@ -113,33 +193,40 @@ impl EleProcess {
         }
     }
 
-    /// Check whether the child has exited.
-    /// 
-    /// If it has, close the pty, unregister the dbus object and return true.
-    async fn check_exited(&mut self, object_server: &ObjectServer, id: usize) -> Result<bool, Box<dyn error::Error>> {
-        // the child can only have exited if it has been started
-        if let Some(child) = self.child.as_mut() {
-            // let-chains are unstable
-            if child.try_wait()?.is_some() {
-                debug!("process {id} has exited; closing pty");
-                let pty = self.pty.take().expect("running process doesn't have a pty");
+    /// Waits for the child to exit, then emits the `Exited` signal, closes
+    /// its pty and deregisters its dbus object.
+    ///
+    /// This owns the `Child` for the lifetime of the process, so `.wait()`
+    /// can simply be `.await`ed instead of polling `try_wait()`.
+    async fn reap(
+        mut child: Child, connection: Connection,
+        signal_emitter: SignalEmitter<'static>, path: String,
+    ) {
+        let (code, signal) = match child.wait().await {
+            Ok(status) => (status.code().unwrap_or(0), status.signal().unwrap_or(0)),
+            Err(e) => {
+                // we can't know how the process actually exited, but we still
+                // have to report *something*, or the client hangs forever
+                // waiting for the `Exited` signal
+                error!("failed to wait for process at {path}: {e}");
+                (-1, 0)
+            }
+        };
+        debug!("process at {path} exited with code {code}, signal {signal}; closing pty");
+        if let Err(e) = Self::exited(&signal_emitter, code, signal).await {
+            error!("failed to emit Exited signal for {path}: {e}");
+        }
+        let object_server = connection.object_server();
+        let iface_ref: Result<InterfaceRef<EleProcess>, _> = object_server.interface(&path).await;
+        if let Ok(iface_ref) = iface_ref {
+            if let Some(pty) = iface_ref.get_mut().await.pty.take() {
                 // dropping a pty doesn't seem to close it?
                 unsafe { OwnedFd::from_raw_fd(pty.as_raw_fd()) };
-                // deregister the whole object
-                if matches!(
-                    object_server.remove::<EleProcess, _>(format!("/de/ytvwld/Ele/{id}")).await,
-                    Ok(true)
-                ) {
-                    Ok(true)
-                } else {
-                    error!("failed to unregister process {id}");
-                    Err("failed to unregister process")?
-                }
-            } else {
-                Ok(false)
             }
-        } else {
-            Ok(false)
+        }
+        // deregister the whole object
+        if !matches!(object_server.remove::<EleProcess, _>(&path).await, Ok(true)) {
+            error!("failed to unregister process at {path}");
         }
     }
 }
@@ -152,10 +239,11 @@ impl EleProcess {
         environ: HashMap<&str, &str>,
     ) -> Result<(), Error> {
         self.check_caller(header)?;
-        if self.child.is_some() {
+        if self.pid.is_some() {
             return Err(Error::FileExists("can't set environ after the process has been started".to_string()));
         }
         debug!("setting environment...");
+        self.env_overridden.extend(environ.keys().map(|k| k.to_string()));
         self.command.envs(environ.iter());
         Ok(())
     }
@@ -166,7 +254,7 @@ impl EleProcess {
         path: &str,
     ) -> Result<(), Error> {
         self.check_caller(header)?;
-        if self.child.is_some() {
+        if self.pid.is_some() {
             return Err(Error::FileExists("can't set cwd after the process has been started".to_string()));
         }
         debug!("setting directory to {path}...");
@@ -174,29 +262,74 @@ impl EleProcess {
         Ok(())
     }
 
+    async fn signal(
+        &mut self,
+        #[zbus(header)] header: Header<'_>,
+        signum: i32,
+    ) -> Result<(), Error> {
+        self.check_caller(header)?;
+        let pid = self.pid.ok_or(
+            Error::Failed("process hasn't been started yet".to_string())
+        )?;
+        let signal = Signal::try_from(signum).map_err(
+            |e| Error::InvalidArgs(e.to_string())
+        )?;
+        debug!("sending {signal} to process {pid}...");
+        kill(Pid::from_raw(pid as i32), signal).map_err(
+            |e| Error::IOError(e.to_string())
+        )?;
+        Ok(())
+    }
+
     async fn resize(
         &mut self,
         #[zbus(header)] header: Header<'_>,
-    ) -> Result<String, Error> {
+        rows: u16, cols: u16,
+    ) -> Result<(), Error> {
         self.check_caller(header)?;
-        // TODO: pty.resize
-        todo!()
+        debug!("resizing pty to {rows}x{cols}...");
+        self.pty.as_ref().ok_or(
+            Error::Failed("process doesn't have a pty".to_string())
+        )?.resize(Size::new(rows, cols)).map_err(
+            |e| Error::IOError(e.to_string())
+        )?;
+        Ok(())
     }
-        
+
     async fn spawn(
         &mut self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_emitter)] signal_emitter: SignalEmitter<'_>,
         #[zbus(header)] header: Header<'_>,
     ) -> Result<Fd, Error> {
         self.check_caller(header)?;
-        if self.child.is_some() {
+        if self.pid.is_some() {
             return Err(Error::FileExists("process is already running".to_string()));
         }
+        // apply the target user's HOME/USER/SHELL, unless the caller already
+        // set them via `environment()`
+        for (key, value) in &self.user_env_defaults {
+            if !self.env_overridden.contains(*key) {
+                self.command.env(key, value);
+            }
+        }
         debug!("spawning process...");
-        self.child = Some(self.command.spawn(&self.pty.as_ref().unwrap().pts().map_err(
+        let child = self.command.spawn(&self.pty.as_ref().unwrap().pts().map_err(
             |e| Error::SpawnFailed(e.to_string())
-        )?).map_err(|e| Error::SpawnFailed(e.to_string()))?);
+        )?).map_err(|e| Error::SpawnFailed(e.to_string()))?;
+        self.pid = child.id();
+        // reap the child as soon as it exits instead of polling for it
+        tokio::spawn(Self::reap(
+            child, connection.clone(), signal_emitter.to_owned(), self.path.clone(),
+        ));
         Ok(Fd::Borrowed(self.pty.as_ref().unwrap().as_fd()))
     }
+
+    /// Emitted once the process has exited, carrying either its exit code
+    /// or the number of the signal that terminated it (with the other
+    /// field set to 0).
+    #[zbus(signal)]
+    async fn exited(signal_emitter: &SignalEmitter<'_>, code: i32, signal: i32) -> zbus::Result<()>;
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -206,28 +339,13 @@ async fn main() -> Result<(), Box<dyn error::Error>> {
     }
     env_logger::init();
     debug!("Establishing connection to dbus...");
-    let conn = connection::Builder::system()?
+    let _conn = connection::Builder::system()?
         .name("de.ytvwld.Ele")?
         .serve_at("/de/ytvwld/Ele", EleD::new())?
         .build()
         .await?;
 
-    // loop through the processes to see which has stopped
-    loop {
-        trace!("checking for processes that have exited...");
-        let len = PROCESS_IDS.read().await.len();
-        for id_idx in 0..len {
-            let id = {
-                let lock = PROCESS_IDS.read().await;
-                *lock.get(id_idx).expect("failed to get process id")
-            };
-            let process: InterfaceRef<EleProcess> = conn.object_server()
-                .interface(format!("/de/ytvwld/Ele/{id}")).await?;
-            if process.get_mut().await.check_exited(conn.object_server().deref(), id).await? {
-                PROCESS_IDS.write().await.remove(id_idx);
-                break;
-            };
-        }
-        sleep(Duration::from_secs(2)).await;
-    }
-}
\ No newline at end of file
+    // processes reap themselves as soon as they exit, so all that's left
+    // to do is keep the connection alive
+    std::future::pending::<Result<(), Box<dyn error::Error>>>().await
+}